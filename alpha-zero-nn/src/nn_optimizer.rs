@@ -1,10 +1,180 @@
 use crate::NN;
 use game::Game;
 use tch::{
-    nn::{Optimizer, OptimizerConfig},
+    nn::{Adam, AdamW, Optimizer, OptimizerConfig, RmsProp, Sgd, VarStore},
     no_grad, Kind, TchError, Tensor,
 };
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Which `tch::nn` optimizer algorithm the master fp32 weights are trained with.
+pub enum OptimizerKind {
+    /// Stochastic gradient descent, optionally with momentum.
+    Sgd { momentum: f64, nesterov: bool },
+    /// Adam.
+    Adam { beta1: f64, beta2: f64, eps: f64 },
+    /// Adam with decoupled weight decay.
+    AdamW {
+        beta1: f64,
+        beta2: f64,
+        eps: f64,
+        weight_decay: f64,
+    },
+    /// RMSProp.
+    RmsProp { alpha: f64, eps: f64, momentum: f64 },
+}
+
+impl OptimizerKind {
+    /// Builds the `tch::nn` optimizer this kind describes against `vs` (the master fp32
+    /// variable store), applying weight decay only where the kind specifies one.
+    fn build(&self, vs: &VarStore, lr: f64) -> Result<Optimizer, TchError> {
+        match *self {
+            OptimizerKind::Sgd { momentum, nesterov } => Sgd {
+                momentum,
+                nesterov,
+                ..Default::default()
+            }
+            .build(vs, lr),
+            OptimizerKind::Adam { beta1, beta2, eps } => Adam {
+                beta1,
+                beta2,
+                eps,
+                ..Default::default()
+            }
+            .build(vs, lr),
+            OptimizerKind::AdamW {
+                beta1,
+                beta2,
+                eps,
+                weight_decay,
+            } => AdamW {
+                beta1,
+                beta2,
+                eps,
+                wd: weight_decay,
+                ..Default::default()
+            }
+            .build(vs, lr),
+            OptimizerKind::RmsProp {
+                alpha,
+                eps,
+                momentum,
+            } => RmsProp {
+                alpha,
+                eps,
+                momentum,
+                ..Default::default()
+            }
+            .build(vs, lr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The floating-point format used for the cloned (compute) weights during mixed-precision
+/// training.
+pub enum Precision {
+    /// IEEE 754 half precision. Has a 5-bit exponent, so it can underflow/overflow and needs
+    /// loss scaling to keep gradients in range.
+    Fp16,
+    /// Brain floating point. Shares fp32's 8-bit exponent, so it doesn't need loss scaling.
+    Bf16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Configuration for a [`LossScaler`].
+pub struct LossScalerConfig {
+    /// The scale used before any growth/backoff has been applied.
+    pub init_scale: f32,
+    /// The factor the scale is multiplied by after `growth_interval` consecutive clean steps.
+    pub growth_factor: f32,
+    /// The factor the scale is multiplied by as soon as an overflowing step is seen.
+    pub backoff_factor: f32,
+    /// The number of consecutive clean steps required before the scale is grown.
+    pub growth_interval: usize,
+    /// The scale is never allowed to drop below this value.
+    pub min_scale: f32,
+    /// The scale is never allowed to grow above this value.
+    pub max_scale: f32,
+}
+
+impl Default for LossScalerConfig {
+    fn default() -> Self {
+        Self {
+            init_scale: (2 << 15) as f32,
+            growth_factor: 2f32,
+            backoff_factor: 0.5f32,
+            growth_interval: 2000,
+            min_scale: 1f32,
+            max_scale: (2 << 23) as f32,
+        }
+    }
+}
+
+/// A dynamic loss scaler with hysteresis.
+///
+/// The scale is backed off the moment an overflowing (inf/nan) gradient is seen, but is only
+/// grown again after a run of `growth_interval` consecutive clean steps. This avoids the
+/// oscillation a naive "halve on overflow, double every N steps" scheme produces once the scale
+/// settles near the edge of what the network can tolerate.
+#[derive(Debug, Clone)]
+pub struct LossScaler {
+    config: LossScalerConfig,
+    scale: f32,
+    good_steps: usize,
+    bad_steps: usize,
+    skipped_steps: usize,
+}
+
+impl LossScaler {
+    /// Creates a new loss scaler starting at `config.init_scale`.
+    pub fn new(config: LossScalerConfig) -> Self {
+        Self {
+            scale: config.init_scale,
+            config,
+            good_steps: 0,
+            bad_steps: 0,
+            skipped_steps: 0,
+        }
+    }
+
+    /// The current loss scale.
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// The running count of steps skipped because of an overflowing gradient.
+    pub fn skipped_steps(&self) -> usize {
+        self.skipped_steps
+    }
+
+    /// The number of consecutive steps that overflowed, most recently.
+    pub fn bad_steps(&self) -> usize {
+        self.bad_steps
+    }
+
+    /// Records the outcome of a step and adjusts the scale accordingly.
+    ///
+    /// On overflow the scale is backed off immediately. Otherwise the step is counted towards
+    /// `growth_interval`, and the scale is only grown once that many clean steps have passed in a
+    /// row.
+    pub fn update(&mut self, overflow: bool) {
+        if overflow {
+            self.scale = (self.scale * self.config.backoff_factor).max(self.config.min_scale);
+            self.good_steps = 0;
+            self.bad_steps += 1;
+            self.skipped_steps += 1;
+        } else {
+            self.bad_steps = 0;
+            self.good_steps += 1;
+
+            if self.good_steps >= self.config.growth_interval {
+                self.scale = (self.scale * self.config.growth_factor).min(self.config.max_scale);
+                self.good_steps = 0;
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 /// Configuration for the neural network optimizer.
 pub struct NNOptimizerConfig {
@@ -12,8 +182,44 @@ pub struct NNOptimizerConfig {
     pub lr: f64,
     /// The maximum gradient norm.
     pub gradient_clip_norm: f64,
-    /// The number of training steps after which the gradient scale is updated.
-    pub gradient_scale_update_interval: usize,
+    /// The loss scaler configuration used for fp16 training.
+    pub loss_scaler: LossScalerConfig,
+    /// The mixed-precision format used for the cloned weights.
+    pub precision: Precision,
+    /// The number of micro-batches to accumulate gradients over before an optimizer step is
+    /// taken. `1` reproduces the previous every-call-is-a-step behavior.
+    pub gradient_accumulation_steps: usize,
+    /// The optimizer algorithm used to train the master fp32 weights.
+    pub optimizer: OptimizerKind,
+    /// When `true`, non-finite gradient entries are zeroed out instead of discarding the whole
+    /// update. An update is still skipped if the *fraction* of non-finite entries across all
+    /// params exceeds `overflow_tolerance`.
+    pub prune_non_finite: bool,
+    /// The maximum fraction (in `[0, 1]`) of non-finite gradient entries tolerated before a step
+    /// is treated as an overflow. Only used when `prune_non_finite` is set; `0.0` reproduces the
+    /// previous all-or-nothing behavior.
+    pub overflow_tolerance: f64,
+}
+
+/// The factor the accumulated master gradients must be scaled by after merging
+/// `gradient_accumulation_steps` micro-batches, so the result is the mean (not the sum) of the
+/// per-micro-batch gradients.
+fn accumulation_scale(gradient_accumulation_steps: usize) -> f64 {
+    1f64 / gradient_accumulation_steps as f64
+}
+
+/// Whether the fraction of non-finite gradient elements across a step is high enough that the
+/// step should be treated as an overflow (and the window dropped) rather than pruned in place.
+fn exceeds_overflow_tolerance(
+    non_finite_count: i64,
+    total_count: i64,
+    overflow_tolerance: f64,
+) -> bool {
+    if total_count <= 0 {
+        return false;
+    }
+
+    (non_finite_count as f64 / total_count as f64) > overflow_tolerance
 }
 
 /// A neural network optimizer which supports mixed precision training.
@@ -24,9 +230,9 @@ where
     config: NNOptimizerConfig,
     nn: NN<G>,
     optimizer: Optimizer,
-    gradient_scale: f32,
-    step_count: usize,
+    loss_scaler: LossScaler,
     master_grad_created: bool,
+    accumulated_micro_batches: usize,
 }
 
 impl<G> NNOptimizer<G>
@@ -34,21 +240,34 @@ where
     G: Game,
 {
     /// Creates a new optimizer for the given neural network.
-    pub fn new(
-        config: NNOptimizerConfig,
-        nn: NN<G>,
-        optimizer: impl OptimizerConfig,
-    ) -> Result<Self, TchError> {
-        let optimizer = optimizer.build(&nn.vs_master(), config.lr)?;
+    ///
+    /// When `config.precision` is `Bf16`, the cloned (compute) weights must already be stored as
+    /// `Kind::BFloat16` — this is checked here so a mismatched `NN` fails loudly instead of
+    /// silently running bf16-labelled training in whatever kind the cloned weights happen to be.
+    pub fn new(config: NNOptimizerConfig, nn: NN<G>) -> Result<Self, TchError> {
+        if config.precision == Precision::Bf16 {
+            let mismatched_kind = nn.vs_cloned().variables().into_iter().find_map(|(name, var)| {
+                (var.kind() != Kind::BFloat16).then_some((name, var.kind()))
+            });
+
+            if let Some((name, kind)) = mismatched_kind {
+                return Err(TchError::Kind(format!(
+                    "Precision::Bf16 requires the cloned weights to be stored as Kind::BFloat16, \
+                     but `{name}` is {kind:?}"
+                )));
+            }
+        }
+
+        let optimizer = config.optimizer.build(&nn.vs_master(), config.lr)?;
+        let loss_scaler = LossScaler::new(config.loss_scaler);
 
         Ok(Self {
             config,
             nn,
             optimizer,
-            // default value, but can be changed
-            gradient_scale: (2 << 15) as f32,
-            step_count: 0,
+            loss_scaler,
             master_grad_created: false,
+            accumulated_micro_batches: 0,
         })
     }
 
@@ -64,6 +283,18 @@ where
         &mut self.optimizer
     }
 
+    /// The current loss scale, for logging training stability.
+    pub fn gradient_scale(&self) -> f32 {
+        self.loss_scaler.scale()
+    }
+
+    /// The running count of steps skipped because of an overflowing gradient. Only steps skipped
+    /// under `Precision::Fp16` are counted; under `Precision::Bf16` the loss scaler is not
+    /// updated, so skipped steps there are not included.
+    pub fn skipped_steps(&self) -> usize {
+        self.loss_scaler.skipped_steps()
+    }
+
     /// Performs a single training step.
     /// Returns the total loss, the value loss and the policy loss.
     pub fn step<'g>(
@@ -87,6 +318,13 @@ where
                 std::iter::once(z_iter.clone().next().unwrap()),
                 std::iter::once(policy_iter.clone().next().unwrap()),
             );
+
+            // the priming backward pass above only exists to allocate the master gradients;
+            // zero them so the first accumulation window starts from a clean slate.
+            for (_, param) in &mut self.nn.vs_master().variables() {
+                param.zero_grad();
+            }
+
             self.master_grad_created = true;
         }
 
@@ -94,10 +332,26 @@ where
             .nn
             .loss(true, batch_size, game_iter, z_iter, policy_iter);
         let loss = &v_loss + &pi_loss;
-        let gradient_scale = Tensor::from_slice(&[self.gradient_scale]).to(self.nn.config().device);
+
+        // bf16 shares fp32's exponent range, so there's no need to scale the loss to protect
+        // against gradient underflow the way fp16 requires.
+        let use_loss_scale = self.config.precision == Precision::Fp16;
+        // `new` already checked the cloned weights are Kind::BFloat16 when precision is Bf16, so
+        // the compute kind is driven by the precision setting rather than trusting the NN's own
+        // (fp16-oriented) `config().kind` in that case.
+        let compute_kind = match self.config.precision {
+            Precision::Fp16 => self.nn.config().kind,
+            Precision::Bf16 => Kind::BFloat16,
+        };
+        let gradient_scale =
+            Tensor::from_slice(&[self.loss_scaler.scale()]).to(self.nn.config().device);
         let gradient_scale_inv =
-            Tensor::from_slice(&[1f32 / self.gradient_scale]).to(self.nn.config().device);
-        let scaled_loss = (&loss * &gradient_scale).to_kind(self.nn.config().kind);
+            Tensor::from_slice(&[1f32 / self.loss_scaler.scale()]).to(self.nn.config().device);
+        let scaled_loss = if use_loss_scale {
+            (&loss * &gradient_scale).to_kind(compute_kind)
+        } else {
+            loss.to_kind(compute_kind)
+        };
 
         // zero out gradients for fp16 weights
         for (_, param) in &mut self.nn.vs_cloned().variables() {
@@ -109,27 +363,85 @@ where
 
         let mut skip_update = false;
 
-        for (_, param) in &self.nn.vs_cloned().variables() {
-            let grad = param.grad();
+        if self.config.prune_non_finite {
+            // tolerate isolated non-finite spikes: only treat the step as an overflow once the
+            // fraction of non-finite elements across all params crosses `overflow_tolerance`
+            let mut non_finite_count = 0i64;
+            let mut total_count = 0i64;
 
-            if !grad.defined() {
-                continue;
+            for (_, param) in &self.nn.vs_cloned().variables() {
+                let grad = param.grad();
+
+                if !grad.defined() {
+                    continue;
+                }
+
+                non_finite_count += grad
+                    .isfinite()
+                    .logical_not()
+                    .sum(Kind::Int64)
+                    .int64_value(&[]);
+                total_count += grad.numel() as i64;
             }
 
-            if (grad.isinf().any().int64_value(&[]) != 0)
-                || (grad.isnan().any().int64_value(&[]) != 0)
-            {
-                // inf or nan detected, use lower gradient scale and skip weight update
-                self.gradient_scale *= 0.5f32;
-                self.step_count = 0;
+            let overflowed = exceeds_overflow_tolerance(
+                non_finite_count,
+                total_count,
+                self.config.overflow_tolerance,
+            );
+
+            if overflowed {
+                for (_, param) in &mut self.nn.vs_master().variables() {
+                    param.zero_grad();
+                }
+
+                self.accumulated_micro_batches = 0;
+
+                // the loss scale only protects fp16 compute against underflow; in Bf16 mode it
+                // isn't in use, so there's nothing to back off
+                if use_loss_scale {
+                    self.loss_scaler.update(true);
+                }
+
                 skip_update = true;
+            }
+        } else {
+            for (_, param) in &self.nn.vs_cloned().variables() {
+                let grad = param.grad();
+
+                if !grad.defined() {
+                    continue;
+                }
+
+                if (grad.isinf().any().int64_value(&[]) != 0)
+                    || (grad.isnan().any().int64_value(&[]) != 0)
+                {
+                    // inf or nan detected, drop the whole accumulated window rather than merging
+                    // a bad micro-batch's gradients into it
+                    for (_, param) in &mut self.nn.vs_master().variables() {
+                        param.zero_grad();
+                    }
 
-                break;
+                    self.accumulated_micro_batches = 0;
+
+                    if use_loss_scale {
+                        self.loss_scaler.update(true);
+                    }
+
+                    skip_update = true;
+
+                    break;
+                }
             }
         }
 
         if !skip_update {
-            // copy unscaled gradients into master
+            // merge the unscaled cloned gradients into the master gradients. Scaled by
+            // `accumulation_scale` so that summing `gradient_accumulation_steps` micro-batches
+            // yields their mean, not their sum — otherwise widening the accumulation window would
+            // silently scale up the effective learning rate.
+            let merge_scale = accumulation_scale(self.config.gradient_accumulation_steps);
+
             for (param_cloned, param_master) in self
                 .nn
                 .vs_cloned()
@@ -141,34 +453,50 @@ where
                 let mut grad_master = param_master.grad();
 
                 no_grad(|| {
-                    grad_master
-                        .copy_(&(grad_cloned.detach().to_kind(Kind::Float) * &gradient_scale_inv));
+                    let mut grad_cloned = grad_cloned.detach().to_kind(Kind::Float);
+
+                    if self.config.prune_non_finite {
+                        grad_cloned = grad_cloned.nan_to_num(Some(0f64), Some(0f64), Some(0f64));
+                    }
+
+                    if use_loss_scale {
+                        grad_master.add_(&(grad_cloned * &gradient_scale_inv * merge_scale));
+                    } else {
+                        grad_master.add_(&(grad_cloned * merge_scale));
+                    }
                 });
             }
 
-            // now gradients are prepared for fp32 weights, run optimizer
-            self.optimizer
-                .clip_grad_norm(self.config.gradient_clip_norm);
-            self.optimizer.step();
-            self.step_count += 1;
+            self.accumulated_micro_batches += 1;
 
-            if self.config.gradient_scale_update_interval <= self.step_count {
-                // increase gradient scale
-                self.gradient_scale *= 2f32;
-                self.step_count = 0;
-            }
+            if self.accumulated_micro_batches >= self.config.gradient_accumulation_steps {
+                // merged gradients for the whole window are prepared for fp32 weights, run optimizer
+                self.optimizer
+                    .clip_grad_norm(self.config.gradient_clip_norm);
+                self.optimizer.step();
 
-            // update fp16 weights
-            for (param_master, mut param_cloned) in self
-                .nn
-                .vs_master()
-                .trainable_variables()
-                .into_iter()
-                .zip(self.nn.vs_cloned().trainable_variables().into_iter())
-            {
-                no_grad(|| {
-                    param_cloned.copy_(&param_master.detach().to_kind(param_cloned.kind()));
-                });
+                if use_loss_scale {
+                    self.loss_scaler.update(false);
+                }
+
+                // update fp16 weights
+                for (param_master, mut param_cloned) in self
+                    .nn
+                    .vs_master()
+                    .trainable_variables()
+                    .into_iter()
+                    .zip(self.nn.vs_cloned().trainable_variables().into_iter())
+                {
+                    no_grad(|| {
+                        param_cloned.copy_(&param_master.detach().to_kind(param_cloned.kind()));
+                    });
+                }
+
+                for (_, param) in &mut self.nn.vs_master().variables() {
+                    param.zero_grad();
+                }
+
+                self.accumulated_micro_batches = 0;
             }
         }
 
@@ -182,11 +510,105 @@ where
 
 #[cfg(test)]
 mod tests {
+    use super::{accumulation_scale, exceeds_overflow_tolerance, LossScaler, LossScalerConfig};
     use tch::{
         nn::{linear, Module, VarStore},
         Device, Tensor,
     };
 
+    fn test_loss_scaler_config() -> LossScalerConfig {
+        LossScalerConfig {
+            init_scale: 8f32,
+            growth_factor: 2f32,
+            backoff_factor: 0.5f32,
+            growth_interval: 3,
+            min_scale: 1f32,
+            max_scale: 64f32,
+        }
+    }
+
+    #[test]
+    fn loss_scaler_backs_off_on_overflow_and_clamps_to_min_scale() {
+        let mut scaler = LossScaler::new(LossScalerConfig {
+            min_scale: 4f32,
+            ..test_loss_scaler_config()
+        });
+
+        scaler.update(true);
+        assert_eq!(scaler.scale(), 4f32);
+        assert_eq!(scaler.bad_steps(), 1);
+        assert_eq!(scaler.skipped_steps(), 1);
+
+        // already at min_scale: another overflow must clamp rather than go below it
+        scaler.update(true);
+        assert_eq!(scaler.scale(), 4f32);
+        assert_eq!(scaler.bad_steps(), 2);
+        assert_eq!(scaler.skipped_steps(), 2);
+    }
+
+    #[test]
+    fn loss_scaler_grows_only_after_growth_interval_clean_steps() {
+        let mut scaler = LossScaler::new(test_loss_scaler_config());
+
+        scaler.update(false);
+        scaler.update(false);
+        assert_eq!(scaler.scale(), 8f32, "scale must not grow before growth_interval");
+
+        scaler.update(false);
+        assert_eq!(scaler.scale(), 16f32);
+        assert_eq!(scaler.skipped_steps(), 0);
+        assert_eq!(scaler.bad_steps(), 0);
+    }
+
+    #[test]
+    fn loss_scaler_clamps_growth_to_max_scale() {
+        let mut scaler = LossScaler::new(LossScalerConfig {
+            init_scale: 48f32,
+            growth_interval: 1,
+            ..test_loss_scaler_config()
+        });
+
+        scaler.update(false);
+        assert_eq!(scaler.scale(), 64f32);
+    }
+
+    #[test]
+    fn loss_scaler_overflow_resets_growth_progress() {
+        let mut scaler = LossScaler::new(test_loss_scaler_config());
+
+        scaler.update(false);
+        scaler.update(false);
+        scaler.update(true);
+        scaler.update(false);
+        scaler.update(false);
+
+        // the overflow reset good_steps, so two more clean steps isn't enough to grow yet
+        assert_eq!(scaler.scale(), 4f32);
+    }
+
+    #[test]
+    fn overflow_tolerance_zero_reproduces_all_or_nothing_behavior() {
+        assert!(exceeds_overflow_tolerance(1, 1000, 0.0));
+        assert!(!exceeds_overflow_tolerance(0, 1000, 0.0));
+    }
+
+    #[test]
+    fn overflow_tolerance_allows_rare_spikes_but_not_high_fractions() {
+        assert!(!exceeds_overflow_tolerance(1, 1000, 1e-3));
+        assert!(exceeds_overflow_tolerance(2, 1000, 1e-3));
+    }
+
+    #[test]
+    fn overflow_tolerance_with_no_gradients_never_overflows() {
+        assert!(!exceeds_overflow_tolerance(0, 0, 0.0));
+    }
+
+    #[test]
+    fn accumulation_scale_averages_over_the_window() {
+        assert_eq!(accumulation_scale(1), 1f64);
+        assert_eq!(accumulation_scale(4), 0.25f64);
+    }
+
     #[test]
     fn copy_gradient() {
         let vs = VarStore::new(Device::Cpu);
@@ -1,5 +1,8 @@
 use log::info;
-use nn::{nn_optimizer::NNOptimizerConfig, NNConfig};
+use nn::{
+    nn_optimizer::{LossScalerConfig, NNOptimizerConfig, OptimizerKind, Precision},
+    NNConfig,
+};
 use tch::Device;
 use train::{parallel_mcts_executor::MCTSExecutorConfig, Trainer, TrainerConfig};
 
@@ -26,7 +29,21 @@ fn main() {
             fc0_channels: 1024,
             fc1_channels: 1024,
         },
-        nn_optimizer_config: NNOptimizerConfig { lr: 0.001f64 },
+        nn_optimizer_config: NNOptimizerConfig {
+            lr: 0.001f64,
+            gradient_clip_norm: 1f64,
+            loss_scaler: LossScalerConfig::default(),
+            precision: Precision::Fp16,
+            gradient_accumulation_steps: 1,
+            optimizer: OptimizerKind::AdamW {
+                beta1: 0.9,
+                beta2: 0.999,
+                eps: 1e-8,
+                weight_decay: 1e-4,
+            },
+            prune_non_finite: false,
+            overflow_tolerance: 0f64,
+        },
         replay_buffer_size: 10000,
         episodes: 100,
         mcts_count: 25,